@@ -1,9 +1,39 @@
-use crate::{Address, GossipsBuilder, Layer, Node, NodeProfile, Nodes, ViewBuilder};
+use crate::poldercast::weighted_shuffle::WeightedShuffle;
+use crate::{
+    Address, GossipsBuilder, Layer, MultiGossipsBuilder, Node, NodeProfile, Nodes, PruneBuilder,
+    Topic, ViewBuilder,
+};
 use rand::seq::SliceRandom;
 use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
 
 const VICINITY_MAX_VIEW_SIZE: usize = 20;
 const VICINITY_MAX_GOSSIP_LENGTH: usize = 10;
+const VICINITY_MIN_STAKE: u64 = 0;
+const VICINITY_MIN_STAKED_NODE_COUNT: usize = usize::MAX;
+/// Number of populate rounds a pruned address is excluded from the view for,
+/// before it becomes eligible to be selected again.
+const VICINITY_PRUNE_TIMEOUT_ROUNDS: u32 = 5;
+/// A neighbor must have delivered at least this many duplicate events before
+/// it becomes a pruning candidate.
+const VICINITY_PRUNE_DUPLICATE_THRESHOLD: u32 = 3;
+/// Number of populate rounds a delivery-tracking entry is kept without being
+/// refreshed before it's evicted, bounding `delivery_origins` memory.
+const VICINITY_DELIVERY_HISTORY_ROUNDS: u32 = 10;
+/// Size, as a multiple of `max`, of the proximity-sorted window that
+/// stake-weighted sampling draws from. Keeps stake weighting from
+/// overriding proximity relevance entirely.
+const VICINITY_STAKE_WINDOW_FACTOR: usize = 3;
+
+/// A view sub-population grouped by stake bucket (see [`Vicinity::stake_bucket`]),
+/// capped by a weight derived from the bucket's own stake magnitude so that
+/// propagation originating from a low-stake peer cannot draw unbounded amounts
+/// from the highest-stake buckets.
+#[derive(Clone, Debug, Default)]
+struct StakeBucketView {
+    addresses: Vec<Address>,
+    weight: u64,
+}
 
 /// The Vicinity module is responsible for maintaining interest-induced
 /// random links, that is, randomly chosen links between nodes that share
@@ -12,9 +42,28 @@ const VICINITY_MAX_GOSSIP_LENGTH: usize = 10;
 /// events to arbitrary subscribers of a topic.
 #[derive(Clone, Debug)]
 pub struct Vicinity {
-    view: Vec<Address>,
+    buckets: BTreeMap<u32, StakeBucketView>,
     max_view_size: usize,
     max_gossip_length: usize,
+    stake_weighted: bool,
+    min_stake: u64,
+    min_staked_node_count: usize,
+    /// This node's own stake, refreshed on every `populate`, used as the
+    /// origin stake when merging bucket views in `view()`.
+    own_stake: u64,
+    /// Addresses currently pruned, mapped to the last round through which
+    /// they stay excluded; they become eligible again in the round after.
+    pruned: HashMap<Address, u32>,
+    prune_timeout_rounds: u32,
+    /// Which neighbor first delivered each (origin, topic) pair, and the
+    /// round that delivery was last seen in. Any other neighbor that later
+    /// delivers the same pair is recorded as sending a duplicate in
+    /// `duplicate_counts`. Entries not refreshed within
+    /// `VICINITY_DELIVERY_HISTORY_ROUNDS` are evicted in `populate`, the same
+    /// way `pruned` expires against `round`, so this doesn't grow unbounded.
+    delivery_origins: HashMap<(Address, Topic), (Address, u32)>,
+    duplicate_counts: HashMap<Address, u32>,
+    round: u32,
 }
 impl Layer for Vicinity {
     fn alias(&self) -> &'static str {
@@ -22,39 +71,67 @@ impl Layer for Vicinity {
     }
 
     fn reset(&mut self) {
-        self.view.clear()
+        self.buckets.clear()
     }
 
     fn populate(&mut self, identity: &NodeProfile, all_nodes: &Nodes) {
-        self.view = self.select_closest_nodes(
-            identity,
-            all_nodes
-                .available_nodes()
-                .iter()
-                .filter(|id| Some(*id) != identity.address())
-                .filter_map(|id| all_nodes.peek(id))
-                .collect(),
-            self.max_view_size,
-        )
+        self.round += 1;
+        let round = self.round;
+        self.pruned
+            .retain(|_, expires_at| !Self::prune_expired(*expires_at, round));
+        self.delivery_origins.retain(|_, (_, seen_round)| {
+            round.saturating_sub(*seen_round) <= VICINITY_DELIVERY_HISTORY_ROUNDS
+        });
+        self.own_stake = identity.stake();
+
+        let candidates = all_nodes
+            .available_nodes()
+            .iter()
+            .filter(|id| Some(*id) != identity.address())
+            .filter(|id| !self.pruned.contains_key(id))
+            .filter_map(|id| all_nodes.peek(id))
+            .collect();
+        let candidates = self.filter_by_stake(candidates);
+
+        let mut grouped: BTreeMap<u32, Vec<&Node>> = BTreeMap::new();
+        for node in candidates {
+            grouped
+                .entry(Self::stake_bucket(node.profile().stake()))
+                .or_default()
+                .push(node);
+        }
+
+        let bucket_weights: Vec<(u32, u64)> = grouped
+            .keys()
+            .map(|&bucket| (bucket, Self::bucket_weight(bucket)))
+            .collect();
+        let caps = Self::allocate_view_caps(self.max_view_size, &bucket_weights);
+        self.buckets = grouped
+            .into_iter()
+            .map(|(bucket, nodes)| {
+                let weight = Self::bucket_weight(bucket);
+                let cap = caps.get(&bucket).copied().unwrap_or(0);
+                let addresses = self.select_closest_nodes(identity, nodes, cap);
+                (bucket, StakeBucketView { addresses, weight })
+            })
+            .collect();
     }
 
     fn gossips(
         &mut self,
-        _identity: &NodeProfile,
+        identity: &NodeProfile,
         gossips_builder: &mut GossipsBuilder,
         all_nodes: &Nodes,
     ) {
         if let Some(node) = all_nodes.peek(gossips_builder.recipient()) {
-            let gossips = self.select_closest_nodes(
-                node.profile(),
-                all_nodes
-                    .available_nodes()
-                    .iter()
-                    .filter(|id| *id != gossips_builder.recipient())
-                    .filter_map(|id| all_nodes.peek(id))
-                    .collect(),
-                self.max_gossip_length,
-            );
+            let candidates = self
+                .view_for_origin(identity.stake())
+                .into_iter()
+                .filter(|address| address != gossips_builder.recipient())
+                .filter_map(|address| all_nodes.peek(&address))
+                .collect();
+            let gossips =
+                self.select_closest_nodes(node.profile(), candidates, self.max_gossip_length);
             for gossip in gossips {
                 gossips_builder.add(gossip);
             }
@@ -62,20 +139,279 @@ impl Layer for Vicinity {
     }
 
     fn view(&mut self, view_builder: &mut ViewBuilder, all_nodes: &mut Nodes) {
-        for id in self.view.iter() {
-            if let Some(node) = all_nodes.peek_mut(id) {
+        for id in self.view_for_origin(self.own_stake) {
+            if let Some(node) = all_nodes.peek_mut(&id) {
                 view_builder.add(node)
             }
         }
     }
+
+    /// Same as [`Layer::gossips`] but targets every recipient the
+    /// `MultiGossipsBuilder` was seeded with, instead of a single one.
+    /// Each recipient gets its own set of up to `max_gossip_length` gossips,
+    /// chosen from the view by proximity to that recipient, so the total
+    /// amount of gossip generated per call grows with the number of
+    /// recipients.
+    fn gossips_many(
+        &mut self,
+        identity: &NodeProfile,
+        gossips_builder: &mut MultiGossipsBuilder,
+        all_nodes: &Nodes,
+    ) {
+        let pool = self.view_for_origin(identity.stake());
+        for recipient in gossips_builder.recipients().to_vec() {
+            if let Some(node) = all_nodes.peek(&recipient) {
+                let candidates = pool
+                    .iter()
+                    .filter(|address| **address != recipient)
+                    .filter_map(|address| all_nodes.peek(address))
+                    .collect();
+                let gossips = self.select_closest_nodes(
+                    node.profile(),
+                    candidates,
+                    self.max_gossip_length,
+                );
+                for gossip in gossips {
+                    gossips_builder.add(&recipient, gossip);
+                }
+            }
+        }
+    }
+
+    /// Emit a prune request for every neighbor whose observed duplicate
+    /// deliveries (see [`Vicinity::record_delivery`]) reached
+    /// `VICINITY_PRUNE_DUPLICATE_THRESHOLD`, i.e. links that are mostly
+    /// redundant with a better path and can be demoted without hurting
+    /// coverage. The duplicate counters are cleared afterwards so each round
+    /// starts from fresh observations.
+    fn prune(&mut self, prune_builder: &mut PruneBuilder, _all_nodes: &Nodes) {
+        for (address, duplicates) in self.duplicate_counts.drain() {
+            if duplicates >= VICINITY_PRUNE_DUPLICATE_THRESHOLD {
+                prune_builder.add(address);
+            }
+        }
+    }
 }
 impl Vicinity {
     pub fn new(max_view_size: usize, max_gossip_length: usize) -> Self {
         Self {
-            view: Vec::with_capacity(max_view_size),
+            buckets: BTreeMap::new(),
             max_view_size,
             max_gossip_length,
+            stake_weighted: false,
+            min_stake: VICINITY_MIN_STAKE,
+            min_staked_node_count: VICINITY_MIN_STAKED_NODE_COUNT,
+            own_stake: 0,
+            pruned: HashMap::new(),
+            prune_timeout_rounds: VICINITY_PRUNE_TIMEOUT_ROUNDS,
+            delivery_origins: HashMap::new(),
+            duplicate_counts: HashMap::new(),
+            round: 0,
+        }
+    }
+
+    /// Same as [`Vicinity::new`] but additionally biases view membership and
+    /// gossip recipient selection toward higher-staked peers, so well-resourced
+    /// nodes carry more of the propagation load.
+    pub fn with_stake_weighting(max_view_size: usize, max_gossip_length: usize) -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+            max_view_size,
+            max_gossip_length,
+            stake_weighted: true,
+            min_stake: VICINITY_MIN_STAKE,
+            min_staked_node_count: VICINITY_MIN_STAKED_NODE_COUNT,
+            own_stake: 0,
+            pruned: HashMap::new(),
+            prune_timeout_rounds: VICINITY_PRUNE_TIMEOUT_ROUNDS,
+            delivery_origins: HashMap::new(),
+            duplicate_counts: HashMap::new(),
+            round: 0,
+        }
+    }
+
+    /// Same as [`Vicinity::new`] but additionally excludes peers staking less
+    /// than `min_stake` from the view and from outgoing gossip, reducing
+    /// propagation amplification from throwaway/low-resource identities. The
+    /// filter only activates once at least `min_staked_node_count` peers meet
+    /// `min_stake`, so bootstrapping small networks is unaffected.
+    pub fn with_min_stake_filter(
+        max_view_size: usize,
+        max_gossip_length: usize,
+        min_stake: u64,
+        min_staked_node_count: usize,
+    ) -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+            max_view_size,
+            max_gossip_length,
+            stake_weighted: false,
+            min_stake,
+            min_staked_node_count,
+            own_stake: 0,
+            pruned: HashMap::new(),
+            prune_timeout_rounds: VICINITY_PRUNE_TIMEOUT_ROUNDS,
+            delivery_origins: HashMap::new(),
+            duplicate_counts: HashMap::new(),
+            round: 0,
+        }
+    }
+
+    /// Drop peers staking less than `self.min_stake` from `candidates`, but
+    /// only once at least `self.min_staked_node_count` of them meet the
+    /// threshold; otherwise the network is still bootstrapping and the
+    /// filter is bypassed so it doesn't starve the view.
+    fn filter_by_stake<'a>(&self, candidates: Vec<&'a Node>) -> Vec<&'a Node> {
+        if self.min_stake == 0 {
+            return candidates;
+        }
+
+        let staked_count = candidates
+            .iter()
+            .filter(|node| node.profile().stake() >= self.min_stake)
+            .count();
+        if staked_count < self.min_staked_node_count {
+            return candidates;
+        }
+
+        candidates
+            .into_iter()
+            .filter(|node| node.profile().stake() >= self.min_stake)
+            .collect()
+    }
+
+    /// The stake bucket a node falls into: `floor(log2(stake)) + 1`, or `0`
+    /// for an unstaked node. Buckets grow exponentially so that each one
+    /// roughly doubles the stake magnitude of the one below it. Capped at 63
+    /// so that the bucket never exceeds the valid shift range of a `u64`.
+    fn stake_bucket(stake: u64) -> u32 {
+        if stake == 0 {
+            0
+        } else {
+            (64 - stake.leading_zeros()).min(63)
+        }
+    }
+
+    /// The representative stake magnitude of `bucket`, i.e. `2^bucket`.
+    /// Shift is clamped to 63 since [`Vicinity::stake_bucket`] never returns
+    /// more than that, avoiding a shift-overflow panic.
+    fn bucket_weight(bucket: u32) -> u64 {
+        1u64 << bucket.min(63)
+    }
+
+    /// Split `max_view_size` across buckets in proportion to `bucket_weights`,
+    /// using the largest-remainder method: each bucket first gets the integer
+    /// floor of its proportional share, then any seats left over from
+    /// rounding go one at a time to the buckets with the largest fractional
+    /// remainder, largest first. Unlike flooring every bucket up to at least
+    /// one seat, this never allocates more than `max_view_size` seats in
+    /// total when any weight is positive, so a view with many distinct stake
+    /// buckets still stays bounded; a bucket whose proportional share rounds
+    /// down to zero simply contributes no addresses unless it wins a leftover
+    /// seat. If every weight is zero, each bucket gets the full
+    /// `max_view_size`.
+    fn allocate_view_caps(
+        max_view_size: usize,
+        bucket_weights: &[(u32, u64)],
+    ) -> HashMap<u32, usize> {
+        let total_weight: u64 = bucket_weights.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
+            return bucket_weights
+                .iter()
+                .map(|&(bucket, _)| (bucket, max_view_size))
+                .collect();
         }
+
+        let max_view_size = max_view_size as u64;
+        let mut caps: HashMap<u32, usize> = HashMap::new();
+        let mut remainders: Vec<(u32, u64)> = Vec::with_capacity(bucket_weights.len());
+        let mut allocated = 0u64;
+        for &(bucket, weight) in bucket_weights {
+            let numerator = max_view_size * weight;
+            let floor = numerator / total_weight;
+            caps.insert(bucket, floor as usize);
+            remainders.push((bucket, numerator % total_weight));
+            allocated += floor;
+        }
+
+        let mut leftover = max_view_size.saturating_sub(allocated);
+        remainders.sort_unstable_by(|left, right| right.1.cmp(&left.1));
+        for (bucket, _) in remainders {
+            if leftover == 0 {
+                break;
+            }
+            *caps.entry(bucket).or_insert(0) += 1;
+            leftover -= 1;
+        }
+
+        caps
+    }
+
+    /// Merge the per-bucket views for propagating an event that originated
+    /// from a peer staking `origin_stake`. Buckets at or below the origin's
+    /// own bucket are used in full; buckets above it are capped to the
+    /// origin's own weight, so a low-stake origin draws only a small, bounded
+    /// slice of the highest-stake peers instead of flooding them, while a
+    /// high-stake origin effectively sees the full, uncapped view.
+    pub fn view_for_origin(&self, origin_stake: u64) -> Vec<Address> {
+        let origin_bucket = Self::stake_bucket(origin_stake);
+        let origin_weight = Self::bucket_weight(origin_bucket);
+
+        self.buckets
+            .iter()
+            .flat_map(|(&bucket, view)| {
+                if bucket <= origin_bucket {
+                    view.addresses.clone()
+                } else {
+                    let cap = origin_weight.min(view.addresses.len() as u64) as usize;
+                    view.addresses[..cap].to_vec()
+                }
+            })
+            .collect()
+    }
+
+    /// Record that `origin`'s event on `topic` was delivered to us via
+    /// `from`. The first neighbor to deliver a given `(origin, topic)` pair
+    /// is credited as its source link; any other neighbor that delivers the
+    /// same pair afterwards is logging a duplicate, which counts toward that
+    /// neighbor becoming a pruning candidate in [`Layer::prune`]. Each
+    /// delivery refreshes the pair's last-seen round, so actively-repeating
+    /// topics aren't evicted out from under an in-progress comparison.
+    pub fn record_delivery(&mut self, origin: Address, topic: Topic, from: Address) {
+        let round = self.round;
+        match self.delivery_origins.entry((origin, topic)) {
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert((from, round));
+            }
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                let (first, seen_round) = entry.get_mut();
+                if *first != from {
+                    *self.duplicate_counts.entry(from).or_insert(0) += 1;
+                }
+                *seen_round = round;
+            }
+        }
+    }
+
+    /// Apply a prune request for `address`: drop it from the current view
+    /// and keep it out of the view for `self.prune_timeout_rounds` populate
+    /// rounds, after which it becomes eligible to be selected again.
+    pub fn apply_prune(&mut self, address: Address) {
+        for bucket in self.buckets.values_mut() {
+            bucket.addresses.retain(|a| *a != address);
+        }
+        self.pruned
+            .insert(address, self.round + self.prune_timeout_rounds);
+    }
+
+    /// Whether a `pruned` entry recorded with `expires_at` has aged out by
+    /// `round`. An entry inserted at round `r` with `expires_at = r +
+    /// prune_timeout_rounds` stays excluded through populate rounds `r + 1`
+    /// up to and including `r + prune_timeout_rounds`, i.e. exactly
+    /// `prune_timeout_rounds` populate calls, and only expires once `round`
+    /// moves past that.
+    fn prune_expired(expires_at: u32, round: u32) -> bool {
+        expires_at < round
     }
 
     /// select nodes based on the proximity function (see Profile's proximity
@@ -100,6 +436,21 @@ impl Vicinity {
                 .cmp(&to.proximity(right.profile()))
         });
 
+        if self.stake_weighted {
+            // Restrict the weighted draw to a proximity-limited window instead of
+            // the whole candidate pool, so a distant whale-stake node can't crowd
+            // out genuinely close low/no-stake peers. Within that window,
+            // selection is biased by stake rather than a strict top-`max` slice.
+            let window = max.saturating_mul(VICINITY_STAKE_WINDOW_FACTOR).max(max);
+            profiles.truncate(window);
+            let shuffle = WeightedShuffle::new(profiles, |node| node.profile().stake());
+            return shuffle
+                .shuffle(max, &mut rand::thread_rng())
+                .into_iter()
+                .map(|v| v.address().clone())
+                .collect();
+        }
+
         profiles
             .into_iter()
             .take(max)
@@ -111,9 +462,134 @@ impl Vicinity {
 impl Default for Vicinity {
     fn default() -> Self {
         Vicinity {
-            view: Vec::default(),
+            buckets: BTreeMap::new(),
             max_view_size: VICINITY_MAX_VIEW_SIZE,
             max_gossip_length: VICINITY_MAX_GOSSIP_LENGTH,
+            stake_weighted: false,
+            min_stake: VICINITY_MIN_STAKE,
+            min_staked_node_count: VICINITY_MIN_STAKED_NODE_COUNT,
+            own_stake: 0,
+            pruned: HashMap::new(),
+            prune_timeout_rounds: VICINITY_PRUNE_TIMEOUT_ROUNDS,
+            delivery_origins: HashMap::new(),
+            duplicate_counts: HashMap::new(),
+            round: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> Address {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn stake_bucket_groups_by_power_of_two() {
+        assert_eq!(Vicinity::stake_bucket(0), 0);
+        assert_eq!(Vicinity::stake_bucket(1), 1);
+        assert_eq!(Vicinity::stake_bucket(2), 2);
+        assert_eq!(Vicinity::stake_bucket(3), 2);
+        assert_eq!(Vicinity::stake_bucket(4), 3);
+        assert_eq!(Vicinity::stake_bucket(u64::MAX), 63);
+    }
+
+    #[test]
+    fn bucket_weight_is_clamped_to_63() {
+        assert_eq!(Vicinity::bucket_weight(0), 1);
+        assert_eq!(Vicinity::bucket_weight(3), 8);
+        assert_eq!(Vicinity::bucket_weight(63), 1u64 << 63);
+        assert_eq!(Vicinity::bucket_weight(64), 1u64 << 63);
+    }
+
+    #[test]
+    fn allocate_view_caps_never_exceeds_max_view_size() {
+        // 32 buckets of equal weight sharing a view of 20: flooring every
+        // bucket up to at least 1 seat would hand out 32 seats, blowing past
+        // the cap. The largest-remainder method must stay at or under 20.
+        let bucket_weights: Vec<(u32, u64)> = (0..32).map(|bucket| (bucket, 1u64)).collect();
+        let caps = Vicinity::allocate_view_caps(20, &bucket_weights);
+        let total: usize = caps.values().sum();
+        assert!(total <= 20, "allocated {total} seats, expected at most 20");
+    }
+
+    #[test]
+    fn allocate_view_caps_favors_heavier_buckets() {
+        let bucket_weights = vec![(0u32, 1u64), (1u32, 3u64)];
+        let caps = Vicinity::allocate_view_caps(4, &bucket_weights);
+        assert_eq!(caps[&0], 1);
+        assert_eq!(caps[&1], 3);
+    }
+
+    #[test]
+    fn view_for_origin_caps_higher_buckets_to_origin_weight() {
+        let mut vicinity = Vicinity::new(20, 10);
+        vicinity.buckets.insert(
+            0,
+            StakeBucketView {
+                addresses: vec![address(1000), address(1001)],
+                weight: 1,
+            },
+        );
+        vicinity.buckets.insert(
+            3,
+            StakeBucketView {
+                addresses: vec![
+                    address(2000),
+                    address(2001),
+                    address(2002),
+                    address(2003),
+                ],
+                weight: 8,
+            },
+        );
+
+        // Origin bucket 0 (weight 1): its own bucket is used in full, the
+        // higher bucket is capped to the origin's weight (1).
+        let view = vicinity.view_for_origin(0);
+        assert_eq!(view.len(), 3);
+        assert_eq!(&view[..2], &[address(1000), address(1001)]);
+        assert_eq!(&view[2..], &[address(2000)]);
+    }
+
+    #[test]
+    fn prune_expired_excludes_for_exactly_timeout_rounds() {
+        let pruned_at = 10u32;
+        let timeout = VICINITY_PRUNE_TIMEOUT_ROUNDS;
+        let expires_at = pruned_at + timeout;
+
+        for round in (pruned_at + 1)..=(pruned_at + timeout) {
+            assert!(
+                !Vicinity::prune_expired(expires_at, round),
+                "should still be excluded at round {round}"
+            );
+        }
+        assert!(Vicinity::prune_expired(
+            expires_at,
+            pruned_at + timeout + 1
+        ));
+    }
+
+    #[test]
+    fn apply_prune_removes_address_from_buckets_and_records_expiry() {
+        let mut vicinity = Vicinity::new(20, 10);
+        vicinity.round = 10;
+        vicinity.buckets.insert(
+            0,
+            StakeBucketView {
+                addresses: vec![address(1000), address(1001)],
+                weight: 1,
+            },
+        );
+
+        vicinity.apply_prune(address(1000));
+
+        assert_eq!(vicinity.buckets[&0].addresses, vec![address(1001)]);
+        assert_eq!(
+            vicinity.pruned[&address(1000)],
+            10 + VICINITY_PRUNE_TIMEOUT_ROUNDS
+        );
+    }
+}
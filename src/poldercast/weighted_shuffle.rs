@@ -0,0 +1,150 @@
+use rand::Rng;
+
+/// A Fenwick (binary-indexed) tree of cumulative weights, used to draw
+/// elements with probability proportional to their weight without
+/// replacement.
+///
+/// Each draw generates a random value in `[0, total_weight)`, binary-searches
+/// the tree for the index whose cumulative weight range contains it, then
+/// subtracts that index's weight from the tree so it cannot be drawn again.
+/// This is `O(n log n)` for a full shuffle and produces an unbiased weighted
+/// ordering, unlike taking a strict top-`max` slice or repeated independent
+/// sampling. Zero-weight entries are never drawn by weight and are appended
+/// last, in their original order.
+pub struct WeightedShuffle<T> {
+    items: Vec<T>,
+    weights: Vec<u64>,
+    tree: Vec<u64>,
+    total_weight: u64,
+}
+
+impl<T> WeightedShuffle<T> {
+    /// Build a shuffle pool from `items`, each paired with a weight via
+    /// `weight_fn`. Items with a weight of zero are kept but never drawn
+    /// ahead of a positively-weighted item.
+    pub fn new(items: Vec<T>, weight_fn: impl Fn(&T) -> u64) -> Self {
+        let weights: Vec<u64> = items.iter().map(weight_fn).collect();
+        let n = items.len();
+        let mut tree = vec![0u64; n + 1];
+        for (i, &w) in weights.iter().enumerate() {
+            Self::tree_add(&mut tree, i, w);
+        }
+        let total_weight = weights.iter().sum();
+        Self {
+            items,
+            weights,
+            tree,
+            total_weight,
+        }
+    }
+
+    fn tree_add(tree: &mut [u64], index: usize, delta: u64) {
+        let mut i = index + 1;
+        while i < tree.len() {
+            tree[i] = tree[i].wrapping_add(delta);
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// Binary-search the Fenwick tree for the smallest index whose prefix
+    /// sum exceeds `target`, returning its 0-based position in `items`.
+    fn find(&self, target: u64) -> usize {
+        let n = self.weights.len();
+        let mut pos = 0usize;
+        let mut remaining = target;
+        let mut bit_mask = {
+            let mut p = 1usize;
+            while p * 2 <= n {
+                p *= 2;
+            }
+            p
+        };
+        while bit_mask != 0 {
+            let next = pos + bit_mask;
+            if next <= n && self.tree[next] <= remaining {
+                pos = next;
+                remaining -= self.tree[next];
+            }
+            bit_mask >>= 1;
+        }
+        pos
+    }
+
+    /// Draw up to `max` elements without replacement, with probability
+    /// proportional to weight, followed by any remaining zero-weight
+    /// elements (in their original relative order) if `max` was not yet
+    /// reached.
+    pub fn shuffle<R: Rng>(mut self, max: usize, rng: &mut R) -> Vec<T> {
+        let max = max.min(self.items.len());
+        let mut order = Vec::with_capacity(max);
+
+        while order.len() < max && self.total_weight > 0 {
+            let target = rng.gen_range(0..self.total_weight);
+            let index = self.find(target);
+            let weight = self.weights[index];
+            self.weights[index] = 0;
+            Self::tree_add(&mut self.tree, index, weight.wrapping_neg());
+            self.total_weight -= weight;
+            order.push(index);
+        }
+
+        let mut items: Vec<Option<T>> = self.items.into_iter().map(Some).collect();
+        let mut result: Vec<T> = order
+            .into_iter()
+            .filter_map(|index| items[index].take())
+            .collect();
+
+        if result.len() < max {
+            for item in items.into_iter().flatten() {
+                if result.len() >= max {
+                    break;
+                }
+                result.push(item);
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn all_zero_weight_falls_back_to_original_order() {
+        let items = vec!["a", "b", "c"];
+        let shuffle = WeightedShuffle::new(items.clone(), |_| 0);
+        let result = shuffle.shuffle(3, &mut rand::thread_rng());
+        assert_eq!(result, items);
+    }
+
+    #[test]
+    fn max_greater_than_len_returns_every_item_once() {
+        let items = vec![1, 2, 3];
+        let shuffle = WeightedShuffle::new(items, |&w| w as u64);
+        let result = shuffle.shuffle(10, &mut rand::thread_rng());
+        let drawn: HashSet<_> = result.iter().copied().collect();
+        assert_eq!(result.len(), 3);
+        assert_eq!(drawn.len(), 3);
+    }
+
+    #[test]
+    fn single_element_is_returned() {
+        let shuffle = WeightedShuffle::new(vec![42], |&w| w as u64);
+        let result = shuffle.shuffle(1, &mut rand::thread_rng());
+        assert_eq!(result, vec![42]);
+    }
+
+    #[test]
+    fn drawn_items_never_repeat() {
+        let items: Vec<u32> = (0..20).collect();
+        let shuffle = WeightedShuffle::new(items.clone(), |&w| (w as u64) + 1);
+        let result = shuffle.shuffle(12, &mut rand::thread_rng());
+        let drawn: HashSet<_> = result.iter().copied().collect();
+        assert_eq!(result.len(), 12);
+        assert_eq!(drawn.len(), 12);
+        assert!(drawn.iter().all(|item| items.contains(item)));
+    }
+}